@@ -2,6 +2,8 @@ use std::env;
 use std::ffi::CString;
 use std::fs;
 use std::io;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::ptr;
@@ -38,6 +40,35 @@ fn init_log() {
         .init();
 }
 
+#[derive(Clone, Copy)]
+enum RootfsPropagation {
+    Shared,
+    Private,
+    Slave,
+    Unbindable,
+}
+
+impl RootfsPropagation {
+    fn parse(s: &str) -> Result<RootfsPropagation, String> {
+        match s {
+            "shared" => Ok(RootfsPropagation::Shared),
+            "private" => Ok(RootfsPropagation::Private),
+            "slave" => Ok(RootfsPropagation::Slave),
+            "unbindable" => Ok(RootfsPropagation::Unbindable),
+            other => Err(format!("unknown rootfs propagation mode: {}", other)),
+        }
+    }
+
+    fn mount_flag(&self) -> MountFlags {
+        match self {
+            RootfsPropagation::Shared => MountFlags::SHARED,
+            RootfsPropagation::Private => MountFlags::PRIVATE,
+            RootfsPropagation::Slave => MountFlags::SLAVE,
+            RootfsPropagation::Unbindable => MountFlags::UNBINDABLE,
+        }
+    }
+}
+
 fn libc_result<T: Ord>(res: T, happy: T) -> io::Result<T> {
     if res != happy {
         Err(io::Error::last_os_error())
@@ -46,13 +77,44 @@ fn libc_result<T: Ord>(res: T, happy: T) -> io::Result<T> {
     }
 }
 
-fn unshare_mnt() -> io::Result<()> {
-    match libc_result(unsafe { libc::unshare(libc::CLONE_NEWNS) }, 0) {
+fn unshare(flags: libc::c_int) -> io::Result<()> {
+    match libc_result(unsafe { libc::unshare(flags) }, 0) {
         Ok(_) => Ok(()),
         Err(err) => Err(err),
     }
 }
 
+#[derive(Clone, Copy)]
+enum UserMapping {
+    // invoking user maps to uid/gid 0 (root) inside the new user namespace
+    MapRoot,
+    // invoking user keeps their own uid/gid inside the new user namespace
+    MapUser,
+}
+
+// write the single-id mapping selected by `mapping` to /proc/self/{uid,gid}_map,
+// making the pivot_root/bind sequence usable without real root privileges.
+fn setup_id_mapping(mapping: UserMapping, real_uid: libc::uid_t, real_gid: libc::gid_t) -> io::Result<()> {
+    // setgroups must be denied before gid_map can be written by an
+    // unprivileged process
+    fs::write("/proc/self/setgroups", "deny")?;
+
+    let (uid_map, gid_map) = match mapping {
+        UserMapping::MapRoot => (
+            format!("0 {} 1", real_uid),
+            format!("0 {} 1", real_gid),
+        ),
+        UserMapping::MapUser => (
+            format!("{} {} 1", real_uid, real_uid),
+            format!("{} {} 1", real_gid, real_gid),
+        ),
+    };
+    debug!("uid_map: {} gid_map: {}", uid_map, gid_map);
+    fs::write("/proc/self/uid_map", uid_map)?;
+    fs::write("/proc/self/gid_map", gid_map)?;
+    Ok(())
+}
+
 fn pivot_root(new_root: &str, put_old: &str) -> io::Result<()> {
     debug!("pivot root to {} old at {}", new_root, put_old);
     // TODO pass args
@@ -73,7 +135,13 @@ fn pivot_root(new_root: &str, put_old: &str) -> io::Result<()> {
     }
 }
 
-fn mount(src: &str, target: &str, fstype: &str, maybe_flags: Option<MountFlags>) -> io::Result<()> {
+fn mount(
+    src: &str,
+    target: &str,
+    fstype: &str,
+    maybe_flags: Option<MountFlags>,
+    data: Option<&str>,
+) -> io::Result<()> {
     let mut mnt_flags: libc::c_ulong = 0;
 
     if let Some(flags) = maybe_flags {
@@ -114,15 +182,20 @@ fn mount(src: &str, target: &str, fstype: &str, maybe_flags: Option<MountFlags>)
         }
     }
     debug!(
-        "mount {} -> {} fs: {} flags: 0x{:x}",
+        "mount {} -> {} fs: {} flags: 0x{:x} data: {}",
         src,
         target,
         if fstype == "" { "(none)" } else { fstype },
-        mnt_flags
+        mnt_flags,
+        data.unwrap_or("(none)"),
     );
     let c_src = CString::new(src).expect("source must not contain null bytes");
     let c_target = CString::new(target).expect("target must not contain null bytes");
     let c_fstype = CString::new(fstype).expect("fs type must not contain null bytes");
+    let c_data = data.map(|d| CString::new(d).expect("data must not contain null bytes"));
+    let data_ptr = c_data
+        .as_ref()
+        .map_or(ptr::null(), |d| d.as_ptr() as *const libc::c_void);
     match libc_result(
         unsafe {
             libc::mount(
@@ -130,7 +203,7 @@ fn mount(src: &str, target: &str, fstype: &str, maybe_flags: Option<MountFlags>)
                 c_target.as_ptr(),
                 c_fstype.as_ptr(),
                 mnt_flags,
-                ptr::null(),
+                data_ptr,
             )
         },
         0,
@@ -140,6 +213,15 @@ fn mount(src: &str, target: &str, fstype: &str, maybe_flags: Option<MountFlags>)
     }
 }
 
+fn mknod(path: &str, mode: libc::mode_t, dev: libc::dev_t) -> io::Result<()> {
+    debug!("mknod {} mode: 0o{:o} dev: {:?}", path, mode, dev);
+    let c_path = CString::new(path).expect("path must not contain null bytes");
+    match libc_result(unsafe { libc::mknod(c_path.as_ptr(), mode, dev) }, 0) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
 fn umount(target: &str, maybe_flags: Option<UmountFlags>) -> io::Result<()> {
     let mut umnt_flags: libc::c_int = 0;
     if let Some(flags) = maybe_flags {
@@ -159,6 +241,178 @@ fn umount(target: &str, maybe_flags: Option<UmountFlags>) -> io::Result<()> {
     }
 }
 
+// mountinfo escapes space, tab, newline and backslash as octal sequences
+fn unescape_mountinfo_path(s: &str) -> String {
+    s.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+// list mount points at or below `prefix`, deepest (most path components)
+// first, so children are unmounted before their parents
+fn mount_points_under(mountinfo: &mut fs::File, prefix: &str) -> io::Result<Vec<String>> {
+    mountinfo.seek(SeekFrom::Start(0))?;
+    let below = format!("{}/", prefix);
+    let mut points: Vec<String> = BufReader::new(mountinfo)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| {
+            // mount point is the 5th whitespace-separated field, see
+            // proc(5) on /proc/pid/mountinfo
+            line.split_whitespace()
+                .nth(4)
+                .map(unescape_mountinfo_path)
+        })
+        .filter(|point| point == prefix || point.starts_with(&below))
+        .collect();
+    points.sort_by_key(|point| std::cmp::Reverse(point.matches('/').count()));
+    Ok(points)
+}
+
+// recursively tear down every mount at or below `prefix`, deepest first, to
+// handle stacked/nested submounts (e.g. under /dev, /sys, /proc rbinds, or
+// any user-supplied nested mounts) that a single umount() would leave behind
+fn umount_recursive(prefix: &str) -> io::Result<()> {
+    // pin the mountinfo file open before we start tearing anything down,
+    // since /proc itself may live under prefix and disappear mid-teardown
+    let mut mountinfo = fs::File::open("/proc/self/mountinfo")?;
+    loop {
+        let points = mount_points_under(&mut mountinfo, prefix)?;
+        if points.is_empty() {
+            return Ok(());
+        }
+        let mut progress = false;
+        for point in &points {
+            match umount(point, Some(UmountFlags::DETACH)) {
+                Ok(_) => progress = true,
+                Err(err) => debug!("cannot unmount {}: {}", point, err),
+            }
+        }
+        if !progress {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("cannot unmount all mounts under {}", prefix),
+            ));
+        }
+    }
+}
+
+// populate `dev_dir` (a fresh tmpfs) with the canonical set of devices
+// instead of recursively bind-mounting the host's /dev, so the sandboxed
+// command doesn't see the host's full device tree
+fn setup_minimal_dev(dev_dir: &Path) -> io::Result<()> {
+    let dev_dir_str = &dev_dir.to_string_lossy();
+    mount("none", dev_dir_str, "tmpfs", None, None)?;
+
+    let char_device = libc::S_IFCHR | 0o666;
+    let nodes: &[(&str, u32, u32)] = &[
+        ("null", 1, 3),
+        ("zero", 1, 5),
+        ("full", 1, 7),
+        ("random", 1, 8),
+        ("urandom", 1, 9),
+        ("tty", 5, 0),
+        ("ptmx", 5, 2),
+    ];
+    for (name, major, minor) in nodes {
+        let path = dev_dir.join(name);
+        mknod(
+            &path.to_string_lossy(),
+            char_device,
+            unsafe { libc::makedev(*major, *minor) },
+        )?;
+    }
+
+    let pts_dir = dev_dir.join("pts");
+    fs::create_dir(&pts_dir)?;
+    mount("devpts", &pts_dir.to_string_lossy(), "devpts", None, None)?;
+
+    let shm_dir = dev_dir.join("shm");
+    fs::create_dir(&shm_dir)?;
+    mount("none", &shm_dir.to_string_lossy(), "tmpfs", None, None)?;
+
+    symlink("/proc/self/fd", dev_dir.join("fd"))?;
+    symlink("/proc/self/fd/0", dev_dir.join("stdin"))?;
+    symlink("/proc/self/fd/1", dev_dir.join("stdout"))?;
+    symlink("/proc/self/fd/2", dev_dir.join("stderr"))?;
+
+    Ok(())
+}
+
+// one entry of a --config mount list: a source, a destination relative to
+// the rootfs, an fstype, flags and an optional data/options string
+struct MountSpec {
+    source: String,
+    destination: String,
+    fstype: String,
+    flags: Option<MountFlags>,
+    data: Option<String>,
+}
+
+fn parse_mount_flags(s: &str) -> Result<Option<MountFlags>, String> {
+    if s == "none" {
+        return Ok(None);
+    }
+    let mut flags = MountFlags::empty();
+    for part in s.split(',') {
+        let flag = match part {
+            "rec" => MountFlags::REC,
+            "bind" => MountFlags::BIND,
+            "slave" => MountFlags::SLAVE,
+            "shared" => MountFlags::SHARED,
+            "private" => MountFlags::PRIVATE,
+            "unbindable" => MountFlags::UNBINDABLE,
+            other => return Err(format!("unknown mount flag: {}", other)),
+        };
+        flags = flags | flag;
+    }
+    Ok(Some(flags))
+}
+
+// config file format: one mount per line, whitespace separated
+//   <source> <destination> <fstype> <flags> [data]
+// "none" is used as a placeholder where a field does not apply, e.g.
+//   none /var/cache tmpfs none
+//   /opt/host/data data none bind,rec
+// each entry is always forced rslave after mounting (see the --config loop
+// in main()), so a bind source from a "shared" host mount can't leak mount
+// propagation back out into the host namespace
+fn parse_config(path: &str) -> io::Result<Vec<MountSpec>> {
+    let content = fs::read_to_string(path)?;
+    let mut specs = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let err = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path, lineno + 1, what),
+            )
+        };
+        let source = fields.next().ok_or_else(|| err("missing source"))?;
+        let destination = fields.next().ok_or_else(|| err("missing destination"))?;
+        let fstype = fields.next().ok_or_else(|| err("missing fstype"))?;
+        let flags = fields.next().ok_or_else(|| err("missing flags"))?;
+        let data: Vec<&str> = fields.collect();
+        specs.push(MountSpec {
+            source: source.to_string(),
+            destination: destination.trim_start_matches('/').to_string(),
+            fstype: if fstype == "none" { String::new() } else { fstype.to_string() },
+            flags: parse_mount_flags(flags).map_err(|msg| err(&msg))?,
+            data: if data.is_empty() {
+                None
+            } else {
+                Some(data.join(" "))
+            },
+        });
+    }
+    Ok(specs)
+}
+
 fn cmd_from_args(program_args: &[String]) -> Command {
     debug!("command: {}", program_args[0]);
     let mut cmd = Command::new(program_args[0].as_str());
@@ -169,10 +423,87 @@ fn cmd_from_args(program_args: &[String]) -> Command {
     return cmd;
 }
 
+// replace the current process image with program_args[0], so the launched
+// command inherits our PID and signal handling directly instead of running
+// as a child of a lingering bootstrap process
+fn exec_command(program_args: &[String]) -> io::Result<()> {
+    debug!("exec: {}", program_args[0]);
+    // match the spawn path's cmd.env_clear(): execvp otherwise inherits our
+    // own environment untouched
+    for (name, _) in env::vars_os() {
+        env::remove_var(name);
+    }
+    let c_args: Vec<CString> = program_args
+        .iter()
+        .map(|arg| CString::new(arg.as_str()).expect("argument must not contain null bytes"))
+        .collect();
+    let mut c_argv: Vec<*const libc::c_char> = c_args.iter().map(|arg| arg.as_ptr()).collect();
+    c_argv.push(ptr::null());
+    match libc_result(
+        unsafe { libc::execvp(c_args[0].as_ptr(), c_argv.as_ptr()) },
+        0,
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
 fn main() {
     init_log();
 
-    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let mut rootfs_propagation = RootfsPropagation::Slave;
+    let prefix = "--rootfs-propagation=";
+    if let Some(pos) = args.iter().position(|arg| arg.starts_with(prefix)) {
+        let value = args.remove(pos);
+        rootfs_propagation =
+            RootfsPropagation::parse(&value[prefix.len()..]).expect("invalid --rootfs-propagation value");
+    }
+
+    let overlay = if let Some(pos) = args.iter().position(|arg| arg == "--overlay") {
+        args.remove(pos);
+        true
+    } else {
+        false
+    };
+
+    let minimal_dev = if let Some(pos) = args.iter().position(|arg| arg == "--minimal-dev") {
+        args.remove(pos);
+        true
+    } else {
+        false
+    };
+
+    let exec_mode = if let Some(pos) = args.iter().position(|arg| arg == "--exec") {
+        args.remove(pos);
+        true
+    } else {
+        false
+    };
+
+    let mut config_path: Option<String> = None;
+    if let Some(pos) = args.iter().position(|arg| arg == "--config") {
+        args.remove(pos);
+        if pos >= args.len() {
+            panic!("--config requires a file path argument");
+        }
+        config_path = Some(args.remove(pos));
+    }
+
+    let mut user_mapping: Option<UserMapping> = None;
+    if let Some(pos) = args.iter().position(|arg| arg == "--map-root") {
+        args.remove(pos);
+        user_mapping = Some(UserMapping::MapRoot);
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--map-user") {
+        args.remove(pos);
+        if user_mapping.is_some() {
+            panic!("--map-root and --map-user are mutually exclusive");
+        }
+        user_mapping = Some(UserMapping::MapUser);
+    }
+
     if args.len() < 2 {
         panic!("rootfs or command not provided");
     }
@@ -190,70 +521,216 @@ fn main() {
     let scratch_dir = tmp.path();
     debug!("scratch dir: {}", scratch_dir.display());
 
-    debug!("unsharing mount ns");
-    unshare_mnt().expect("failed to unshare mount namespace");
+    // capture real ids before unshare(CLONE_NEWUSER), which resets them as
+    // seen from inside the new user namespace
+    let real_uid = unsafe { libc::getuid() };
+    let real_gid = unsafe { libc::getgid() };
+
+    if let Some(mapping) = user_mapping {
+        debug!("unsharing user and mount ns (rootless)");
+        unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS).expect("failed to unshare user and mount namespace");
+        setup_id_mapping(mapping, real_uid, real_gid).expect("failed to set up uid/gid mapping");
+    } else {
+        debug!("unsharing mount ns");
+        unshare(libc::CLONE_NEWNS).expect("failed to unshare mount namespace");
+    }
     // henceforth we're in a new mount namespace
 
     let scratch_dir_str = &scratch_dir.to_string_lossy();
 
     // make / propagation slave, mounts created in / will not propagate to
     // parent
-    mount("none", "/", "", Some(MountFlags::REC | MountFlags::SLAVE))
-        .expect("cannot make / recursively shared");
-
-    // make scratch dir a mount point as required by pivot_root
-    mount(scratch_dir_str, scratch_dir_str, "", Some(MountFlags::BIND)).expect(&format!(
-        "cannot make {} a mount point",
-        scratch_dir.display()
-    ));
-
-    debug!("mounting rootfs from {} to {}", rootfs_str, scratch_dir_str);
-    // bind mount new rootfs under scratch
-    mount(
-        &rootfs_str,
-        scratch_dir_str,
-        "",
-        Some(MountFlags::REC | MountFlags::BIND),
-    )
-    .expect(&format!(
-        "cannot bind mount rootfs from {} to {}",
-        rootfs.display(),
-        scratch_dir.display()
-    ));
-    // stop propagation of changes to the host
     mount(
         "none",
-        scratch_dir_str,
+        "/",
         "",
         Some(MountFlags::REC | MountFlags::SLAVE),
+        None,
     )
-    .expect(&format!("cannot make rootfs at {} rslave", scratch_dir_str));
+    .expect("cannot make / recursively slave");
+
+    // new_root is what pivot_root will be called with: either the scratch
+    // dir itself (rootfs rbind-mounted directly into it), or the merged
+    // mountpoint of an overlay filesystem keeping the rootfs read-only
+    let new_root: PathBuf = if overlay {
+        // tmpfs under the scratch dir backs the upper and work dirs, so all
+        // writes land there and vanish with the scratch dir on exit
+        let overlay_dir = scratch_dir.join("overlay");
+        fs::create_dir(&overlay_dir).expect("cannot create overlay directory");
+        mount(
+            "none",
+            &overlay_dir.to_string_lossy(),
+            "tmpfs",
+            None,
+            None,
+        )
+        .expect("cannot mount tmpfs for overlay");
+
+        let upper = overlay_dir.join("upper");
+        let work = overlay_dir.join("work");
+        let merged = overlay_dir.join("merged");
+        fs::create_dir(&upper).expect("cannot create overlay upper directory");
+        fs::create_dir(&work).expect("cannot create overlay work directory");
+        fs::create_dir(&merged).expect("cannot create overlay merged directory");
+
+        let data = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            rootfs_str,
+            upper.display(),
+            work.display()
+        );
+        debug!("mounting overlay at {} with {}", merged.display(), data);
+        mount(
+            "overlay",
+            &merged.to_string_lossy(),
+            "overlay",
+            None,
+            Some(&data),
+        )
+        .expect("cannot mount overlay filesystem");
+        // set rootfs propagation as requested (defaults to slave, preserving
+        // the previous hardcoded behavior)
+        mount(
+            "none",
+            &merged.to_string_lossy(),
+            "",
+            Some(MountFlags::REC | rootfs_propagation.mount_flag()),
+            None,
+        )
+        .expect(&format!(
+            "cannot set rootfs propagation at {}",
+            merged.display()
+        ));
+
+        merged
+    } else {
+        // make scratch dir a mount point as required by pivot_root
+        mount(
+            scratch_dir_str,
+            scratch_dir_str,
+            "",
+            Some(MountFlags::BIND),
+            None,
+        )
+        .expect(&format!(
+            "cannot make {} a mount point",
+            scratch_dir.display()
+        ));
+
+        debug!("mounting rootfs from {} to {}", rootfs_str, scratch_dir_str);
+        // bind mount new rootfs under scratch
+        mount(
+            &rootfs_str,
+            scratch_dir_str,
+            "",
+            Some(MountFlags::REC | MountFlags::BIND),
+            None,
+        )
+        .expect(&format!(
+            "cannot bind mount rootfs from {} to {}",
+            rootfs.display(),
+            scratch_dir.display()
+        ));
+        // set rootfs propagation as requested (defaults to slave, preserving
+        // the previous hardcoded behavior)
+        mount(
+            "none",
+            scratch_dir_str,
+            "",
+            Some(MountFlags::REC | rootfs_propagation.mount_flag()),
+            None,
+        )
+        .expect(&format!(
+            "cannot set rootfs propagation at {}",
+            scratch_dir_str
+        ));
+
+        scratch_dir.to_path_buf()
+    };
+    let new_root_str = &new_root.to_string_lossy();
 
     let from_host = ["/dev", "/sys", "/proc"];
     for loc in from_host.iter() {
+        if minimal_dev && *loc == "/dev" {
+            // synthesized below instead of bound in from the host
+            continue;
+        }
         // join with absolute path replaces the path, so drop the leading /
-        let target_path = scratch_dir.join(&loc[1..]);
+        let target_path = new_root.join(&loc[1..]);
         let target = &target_path.to_string_lossy();
         debug!("rbind mounting {} to {}", loc, target);
         // recursive bind
-        mount(loc, &target, "", Some(MountFlags::REC | MountFlags::BIND))
-            .expect(&format!("cannot bind mount {} to {}", loc, target));
+        mount(
+            loc,
+            &target,
+            "",
+            Some(MountFlags::REC | MountFlags::BIND),
+            None,
+        )
+        .expect(&format!("cannot bind mount {} to {}", loc, target));
         // propagate changes form parent only
         mount(
             "none",
             &target,
             "",
             Some(MountFlags::REC | MountFlags::SLAVE),
+            None,
         )
         .expect(&format!("cannot make {} rslave", target_path.display()));
     }
 
+    if minimal_dev {
+        let dev_dir = new_root.join("dev");
+        setup_minimal_dev(&dev_dir).expect(&format!(
+            "cannot set up minimal /dev at {}",
+            dev_dir.display()
+        ));
+    }
+
+    if let Some(path) = config_path {
+        let specs = parse_config(&path)
+            .expect(&format!("cannot read mount config from {}", path));
+        for spec in &specs {
+            let target_path = new_root.join(&spec.destination);
+            fs::create_dir_all(&target_path).expect(&format!(
+                "cannot create mount point {}",
+                target_path.display()
+            ));
+            let target = &target_path.to_string_lossy();
+            debug!(
+                "mounting {} -> {} from config {}",
+                spec.source, target, path
+            );
+            mount(
+                &spec.source,
+                target,
+                &spec.fstype,
+                spec.flags,
+                spec.data.as_deref(),
+            )
+            .expect(&format!("cannot mount {} -> {} from config", spec.source, target));
+            // detach from the source's propagation peer group, same as the
+            // /dev, /sys and /proc binds above;
+            // without this a config entry can clone its host source's
+            // "shared" propagation and leak mount/unmount events back out
+            mount(
+                "none",
+                target,
+                "",
+                Some(MountFlags::REC | MountFlags::SLAVE),
+                None,
+            )
+            .expect(&format!("cannot make {} rslave", target));
+        }
+    }
+
     // setup tmpfs for /tmp (where we can create the old-root)
     mount(
         "none",
-        &scratch_dir.join("tmp").to_string_lossy(),
+        &new_root.join("tmp").to_string_lossy(),
         "tmpfs",
         None,
+        None,
     )
     .expect("cannot mount a new tmpfs");
 
@@ -261,7 +738,7 @@ fn main() {
     let old_root = PathBuf::from("/tmp/old-root");
     let old_root_str = &old_root.to_string_lossy();
     // this it where old root will be put in the before pivot world
-    let put_old = scratch_dir.join("tmp/old-root");
+    let put_old = new_root.join("tmp/old-root");
     let put_old_str = &put_old.to_string_lossy();
     // this is where the scratch dir is in after pivot world
     let scratch_in_old = old_root.join(&scratch_dir.to_string_lossy()[1..]);
@@ -274,16 +751,20 @@ fn main() {
     fs::create_dir(&put_old).expect("cannot create temporary directory for old root");
 
     // pivot root
-    pivot_root(scratch_dir_str, put_old_str)
-        .expect(&format!("cannot pivot root to {}", scratch_dir.display()));
+    pivot_root(new_root_str, put_old_str)
+        .expect(&format!("cannot pivot root to {}", new_root.display()));
 
-    // umount scratch under the old root firs, so that we can remove it
-    umount(&scratch_in_old.to_string_lossy(), None).expect("cannot unmount scratch directory");
-    debug!(
-        "remove scratch directory in old root {}",
-        scratch_in_old.display()
-    );
-    fs::remove_dir(scratch_in_old).expect("cannot remove old scratch location");
+    if !overlay {
+        // scratch dir was bind-mounted onto itself before becoming new_root,
+        // and that self-bind mount is left behind under the old root; umount
+        // it so that we can remove it
+        umount(&scratch_in_old.to_string_lossy(), None).expect("cannot unmount scratch directory");
+        debug!(
+            "remove scratch directory in old root {}",
+            scratch_in_old.display()
+        );
+        fs::remove_dir(scratch_in_old).expect("cannot remove old scratch location");
+    }
 
     // make old root slave, otherwise we would unmount the host root
     mount(
@@ -291,9 +772,10 @@ fn main() {
         old_root_str,
         "",
         Some(MountFlags::REC | MountFlags::SLAVE),
+        None,
     )
     .expect("cannot switch old root to slave");
-    umount(old_root_str, Some(UmountFlags::DETACH)).expect("cannot unmount old root");
+    umount_recursive(old_root_str).expect("cannot unmount old root");
     debug!("remove old root at {} after pivot", &old_root.display());
     fs::remove_dir(old_root).expect("cannot remove old root");
 
@@ -302,6 +784,10 @@ fn main() {
     env::set_current_dir(&new_cwd)
         .expect(&format!("cannot change directory to {}", new_cwd.display()));
 
-    // XXX run the command
-    cmd.status().expect("failed to execute process");
+    if exec_mode {
+        exec_command(&args[1..]).expect("failed to exec process");
+    } else {
+        // XXX run the command
+        cmd.status().expect("failed to execute process");
+    }
 }